@@ -1,21 +1,19 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, Read, Write};
 use std::mem::size_of;
+use std::net::TcpStream;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::PathBuf;
 use std::ptr::addr_of;
-use std::env;
-use std::collections::HashMap;
-use std::collections::HashSet;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Context, Result};
-use async_std::channel::{unbounded, Receiver, Sender};
-use async_std::fs::{read_dir, File};
-use async_std::io::{ReadExt, WriteExt};
-use async_std::net::TcpStream;
-use async_std::path::PathBuf;
-use async_std::prelude::StreamExt;
-use async_std::task;
 use clap::Parser;
-use futures::future;
 use input_event_codes_hashmap::EV;
-use libc::input_event;
+use libc::{input_event, inotify_event};
 
 mod key;
 use key::*;
@@ -45,6 +43,396 @@ struct Args {
     /// Display debug information. Specify twice to show every key event.
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
+    /// Drop hotkey commands sent while disconnected from the LiveSplit server, instead of buffering them until it reconnects
+    #[arg(long)]
+    drop_commands_on_disconnect: bool,
+}
+
+/// Directories watched for keyboards being plugged in or removed.
+const DEVICE_WATCH_DIRS: [&str; 2] = ["/dev/input", "/dev/input/by-path"];
+/// How long to wait after the first inotify event before acting on it, since
+/// the kernel tends to emit several events while a device node settles.
+const INOTIFY_DEBOUNCE: Duration = Duration::from_millis(150);
+/// Max events drained from a single `epoll_wait` call.
+const MAX_EPOLL_EVENTS: usize = 16;
+/// Initial delay before retrying a dropped LiveSplit server connection.
+const INITIAL_RECONNECT_BACKOFF: Duration = Duration::from_millis(250);
+/// Cap on the exponential reconnect backoff.
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// An open keyboard device registered with the epoll set.
+struct Device {
+    file: fs::File,
+    path: PathBuf,
+    /// Keycodes this process currently believes are held down on this
+    /// device, used to reconcile against its real state after a
+    /// `SYN_DROPPED` (see [`HotkeyListener::resync_keys`]).
+    pressed: HashSet<u32>,
+}
+
+/// `SYN_REPORT`, marking the end of a batch of events that occurred at the
+/// same instant (linux/input-event-codes.h).
+const SYN_REPORT: u16 = 0;
+/// `SYN_DROPPED`: the kernel's event buffer overflowed and some events
+/// between this point and the next `SYN_REPORT` were discarded, so any
+/// `KeyState` built from the stream since the last sync may be stale.
+const SYN_DROPPED: u16 = 3;
+/// `KEY_MAX` (linux/input-event-codes.h), the highest keycode `EVIOCGKEY`
+/// reports a bit for.
+const KEY_MAX: usize = 0x2ff;
+/// Size in bytes of the bitmask `EVIOCGKEY` fills in, one bit per keycode
+/// from 0 to [`KEY_MAX`].
+const KEY_BITMASK_LEN: usize = (KEY_MAX + 1 + 7) / 8;
+
+/// Decodes a byte buffer into a sequence of `(type, code, value)` triples,
+/// one per `input_event`. Fields are read with `read_unaligned` rather than
+/// casting the buffer to a `&input_event`, since the buffer has no
+/// guaranteed alignment and dereferencing a misaligned reference is UB even
+/// though the struct itself has no invalid bit patterns.
+struct EventDecoder<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> EventDecoder<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, offset: 0 }
+    }
+}
+
+impl Iterator for EventDecoder<'_> {
+    type Item = (u16, u16, i32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset + size_of::<input_event>() > self.buf.len() {
+            return None;
+        }
+        let ptr = self.buf[self.offset..].as_ptr() as *const input_event;
+        let event = unsafe { ptr.read_unaligned() };
+        self.offset += size_of::<input_event>();
+        Some((event.type_, event.code, event.value))
+    }
+}
+
+/// Build an ioctl request number the way the kernel's `_IOC` macro does
+/// (include/uapi/asm-generic/ioctl.h), since libc doesn't expose the
+/// evdev-specific ioctls directly.
+const fn ioc(dir: u64, ty: u8, nr: u8, size: usize) -> libc::c_ulong {
+    ((dir << 30) | ((ty as u64) << 8) | (nr as u64) | ((size as u64) << 16)) as libc::c_ulong
+}
+
+/// `EVIOCGKEY(len)`: ask the kernel for the current state of every key on a
+/// device, as a bitmask, bypassing whatever this process has seen on the
+/// event stream so far.
+fn query_key_bitmask(fd: RawFd) -> Result<[u8; KEY_BITMASK_LEN]> {
+    let request = ioc(2 /* _IOC_READ */, b'E', 0x18, KEY_BITMASK_LEN);
+    let mut bitmask = [0u8; KEY_BITMASK_LEN];
+    let ret = unsafe { libc::ioctl(fd, request, bitmask.as_mut_ptr()) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error()).context("EVIOCGKEY ioctl failed");
+    }
+    Ok(bitmask)
+}
+
+fn key_bit_set(bitmask: &[u8; KEY_BITMASK_LEN], code: u32) -> bool {
+    let code = code as usize;
+    code <= KEY_MAX && bitmask[code / 8] & (1 << (code % 8)) != 0
+}
+
+/// Put a raw fd in non-blocking mode so a drain loop can tell readable (or,
+/// for a connecting socket, writable) bursts apart from "nothing to report
+/// yet" via `EWOULDBLOCK`/`EINPROGRESS`.
+fn set_nonblocking(fd: RawFd) -> Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL, 0) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error()).context("fcntl(F_GETFL) failed");
+    }
+    let ret = unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error()).context("fcntl(F_SETFL) failed");
+    }
+    Ok(())
+}
+
+/// Clear non-blocking mode, e.g. once a socket has finished connecting and
+/// is going back to being written with ordinary blocking `write_all` calls.
+fn clear_nonblocking(fd: RawFd) -> Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL, 0) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error()).context("fcntl(F_GETFL) failed");
+    }
+    let ret = unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error()).context("fcntl(F_SETFL) failed");
+    }
+    Ok(())
+}
+
+fn epoll_add(epfd: RawFd, fd: RawFd, events: u32) -> Result<()> {
+    let mut event = libc::epoll_event { events, u64: fd as u64 };
+    let ret = unsafe { libc::epoll_ctl(epfd, libc::EPOLL_CTL_ADD, fd, &mut event) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error()).context("epoll_ctl(ADD) failed");
+    }
+    Ok(())
+}
+
+fn epoll_del(epfd: RawFd, fd: RawFd) -> Result<()> {
+    let ret = unsafe { libc::epoll_ctl(epfd, libc::EPOLL_CTL_DEL, fd, std::ptr::null_mut()) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error()).context("epoll_ctl(DEL) failed");
+    }
+    Ok(())
+}
+
+/// Start a non-blocking `connect()` to `host:port`, returning the raw fd
+/// while the connection is still in progress. Driven to completion later by
+/// waiting for the fd to become writable and checking `SO_ERROR`, so the
+/// caller's event loop never blocks on the network.
+fn start_connect(host: &str, port: u16) -> Result<(RawFd, std::net::SocketAddr)> {
+    use std::net::ToSocketAddrs;
+    let addr = (host, port)
+        .to_socket_addrs()
+        .context("Could not resolve LiveSplit server address")?
+        .next()
+        .ok_or_else(|| anyhow!("Could not resolve LiveSplit server address"))?;
+
+    let domain = if addr.is_ipv4() { libc::AF_INET } else { libc::AF_INET6 };
+    let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error()).context("socket() failed");
+    }
+    set_nonblocking(fd)?;
+
+    let (storage, len) = socket_addr_to_sockaddr(addr);
+    let ret = unsafe { libc::connect(fd, &storage as *const _ as *const libc::sockaddr, len) };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINPROGRESS) {
+            unsafe { libc::close(fd) };
+            return Err(err).context("connect() failed");
+        }
+    }
+    Ok((fd, addr))
+}
+
+fn socket_addr_to_sockaddr(addr: std::net::SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let len = match addr {
+        std::net::SocketAddr::V4(v4) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: v4.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(v4.ip().octets()),
+                },
+                sin_zero: [0; 8],
+            };
+            unsafe { std::ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in, sin) };
+            size_of::<libc::sockaddr_in>()
+        }
+        std::net::SocketAddr::V6(v6) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: v6.port().to_be(),
+                sin6_flowinfo: v6.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: v6.ip().octets(),
+                },
+                sin6_scope_id: v6.scope_id(),
+            };
+            unsafe { std::ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in6, sin6) };
+            size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+/// Connection lifecycle for [`ServerConnection`]. `Connecting` holds a
+/// non-blocking socket that has been registered with epoll for `EPOLLOUT`;
+/// the event loop drives it to `Connected` (or back to `Disconnected`) by
+/// calling [`ServerConnection::poll_connect`] once that fd is writable.
+enum ConnState {
+    Disconnected,
+    Connecting(RawFd),
+    Connected(TcpStream),
+}
+
+/// A `TcpStream` to the LiveSplit server that reconnects with exponential
+/// backoff after a write failure, instead of tearing down the whole
+/// program. Commands sent while disconnected are buffered (or dropped, per
+/// `drop_on_disconnect`) and flushed on reconnection.
+///
+/// Reconnecting uses a non-blocking `connect()` driven by the caller's
+/// epoll loop rather than `TcpStream::connect`, since that loop is also
+/// responsible for reading keyboards with low latency and can't afford to
+/// block for however long a blackholed host takes to time out.
+struct ServerConnection {
+    host: String,
+    port: u16,
+    verbose: u8,
+    drop_on_disconnect: bool,
+    epfd: RawFd,
+    state: ConnState,
+    backoff: Duration,
+    next_attempt: Instant,
+    buffered: Vec<u8>,
+}
+
+impl ServerConnection {
+    fn new(
+        host: String,
+        port: u16,
+        verbose: u8,
+        drop_on_disconnect: bool,
+        epfd: RawFd,
+        initial: TcpStream,
+    ) -> Self {
+        Self {
+            host,
+            port,
+            verbose,
+            drop_on_disconnect,
+            epfd,
+            state: ConnState::Connected(initial),
+            backoff: INITIAL_RECONNECT_BACKOFF,
+            next_attempt: Instant::now(),
+            buffered: Vec::new(),
+        }
+    }
+
+    /// The fd to watch for `EPOLLOUT` while a reconnect is in flight, if any.
+    fn connecting_fd(&self) -> Option<RawFd> {
+        match self.state {
+            ConnState::Connecting(fd) => Some(fd),
+            _ => None,
+        }
+    }
+
+    /// Kick off a non-blocking reconnect if one isn't already in flight and
+    /// the backoff delay has elapsed.
+    fn start_reconnect(&mut self) {
+        if !matches!(self.state, ConnState::Disconnected) || Instant::now() < self.next_attempt {
+            return;
+        }
+        match start_connect(&self.host, self.port) {
+            Ok((fd, _addr)) => {
+                if let Err(e) = epoll_add(self.epfd, fd, libc::EPOLLOUT as u32) {
+                    if self.verbose > 0 {
+                        println!("Could not watch LiveSplit reconnect socket: {e:#}");
+                    }
+                    unsafe { libc::close(fd) };
+                    self.schedule_retry();
+                    return;
+                }
+                if self.verbose > 0 {
+                    println!("Connecting to LiveSplit server at {}:{}...", self.host, self.port);
+                }
+                self.state = ConnState::Connecting(fd);
+            }
+            Err(e) => {
+                if self.verbose > 0 {
+                    println!("Could not reconnect to LiveSplit server ({e:#}), retrying in {:?}", self.backoff);
+                }
+                self.schedule_retry();
+            }
+        }
+    }
+
+    /// Call once epoll reports `connecting_fd()` as writable: finish the
+    /// handshake by checking `SO_ERROR`, and flush any buffered commands on
+    /// success.
+    fn poll_connect(&mut self) {
+        let ConnState::Connecting(fd) = self.state else {
+            return;
+        };
+        let _ = epoll_del(self.epfd, fd);
+
+        let mut sock_err: libc::c_int = 0;
+        let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_ERROR,
+                &mut sock_err as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 || sock_err != 0 {
+            let err = if sock_err != 0 {
+                io::Error::from_raw_os_error(sock_err)
+            } else {
+                io::Error::last_os_error()
+            };
+            unsafe { libc::close(fd) };
+            if self.verbose > 0 {
+                println!("Could not reconnect to LiveSplit server ({err}), retrying in {:?}", self.backoff);
+            }
+            self.state = ConnState::Disconnected;
+            self.schedule_retry();
+            return;
+        }
+
+        if let Err(e) = clear_nonblocking(fd) {
+            if self.verbose > 0 {
+                println!("Failed preparing reconnected LiveSplit socket: {e:#}");
+            }
+            unsafe { libc::close(fd) };
+            self.state = ConnState::Disconnected;
+            self.schedule_retry();
+            return;
+        }
+        if self.verbose > 0 {
+            println!("Reconnected to LiveSplit server at {}:{}", self.host, self.port);
+        }
+        let stream = unsafe { TcpStream::from_raw_fd(fd) };
+        self.state = ConnState::Connected(stream);
+        self.backoff = INITIAL_RECONNECT_BACKOFF;
+        if !self.buffered.is_empty() {
+            let buffered = std::mem::take(&mut self.buffered);
+            self.write(&buffered);
+        }
+    }
+
+    fn schedule_retry(&mut self) {
+        self.next_attempt = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_RECONNECT_BACKOFF);
+    }
+
+    fn write(&mut self, command: &[u8]) {
+        let ConnState::Connected(stream) = &mut self.state else {
+            self.queue_or_drop(command);
+            return;
+        };
+        if let Err(e) = stream.write_all(command) {
+            if self.verbose > 0 {
+                println!("Lost connection to LiveSplit server: {e}");
+            }
+            self.state = ConnState::Disconnected;
+            self.next_attempt = Instant::now();
+            self.queue_or_drop(command);
+        }
+    }
+
+    /// Send `command`, kicking off a reconnect first if the connection was
+    /// previously lost and the backoff delay has elapsed.
+    fn send(&mut self, command: &[u8]) {
+        if matches!(self.state, ConnState::Disconnected) {
+            self.start_reconnect();
+        }
+        self.write(command);
+    }
+
+    fn queue_or_drop(&mut self, command: &[u8]) {
+        if self.drop_on_disconnect {
+            if self.verbose > 0 {
+                println!("Dropping command while disconnected from LiveSplit server");
+            }
+        } else {
+            self.buffered.extend_from_slice(command);
+        }
+    }
 }
 
 struct HotkeyListener {
@@ -159,139 +547,654 @@ impl HotkeyListener {
         Ok(enabled)
     }
 
-    async fn listen_keyboard(sender: Sender<(u32, bool)>, path: PathBuf) -> Result<()> {
-        let ev_key = EV["KEY"] as u16;
-        let mut file = File::open(path).await?;
+    fn open_device(epfd: RawFd, path: PathBuf) -> Result<Device> {
+        let file = fs::File::open(&path).with_context(|| format!("Failed to open {:?}", path))?;
+        set_nonblocking(file.as_raw_fd())?;
+        epoll_add(epfd, file.as_raw_fd(), libc::EPOLLIN as u32)?;
+        Ok(Device { file, path, pressed: HashSet::new() })
+    }
+
+    /// Open an inotify instance watching [`DEVICE_WATCH_DIRS`] for nodes
+    /// appearing or disappearing, returning it alongside a map from watch
+    /// descriptor to the directory it watches (needed to rebuild full paths
+    /// from the events it reports).
+    fn init_inotify() -> Result<(fs::File, HashMap<i32, &'static str>)> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("inotify_init1 failed");
+        }
+        let mask = (libc::IN_CREATE | libc::IN_DELETE | libc::IN_MOVED_TO | libc::IN_MOVED_FROM) as u32;
+        let mut watches = HashMap::new();
+        for dir in DEVICE_WATCH_DIRS {
+            let c_dir = CString::new(dir).context("Device directory contains a NUL byte")?;
+            let wd = unsafe { libc::inotify_add_watch(fd, c_dir.as_ptr(), mask) };
+            if wd < 0 {
+                return Err(io::Error::last_os_error())
+                    .with_context(|| format!("inotify_add_watch({dir}) failed"));
+            }
+            watches.insert(wd, dir);
+        }
+        let file = unsafe { fs::File::from_raw_fd(fd) };
+        Ok((file, watches))
+    }
+
+    /// Read every pending inotify event and translate the ones that refer to
+    /// a `*-event-kbd` node into `(path, created)` pairs.
+    fn read_inotify_events(
+        file: &mut fs::File,
+        watches: &HashMap<i32, &'static str>,
+    ) -> Result<Vec<(PathBuf, bool)>> {
+        let mut events = Vec::new();
+        let mut buf = [0u8; 4096];
         loop {
-            let (type_, code, value) = {
-                let mut event_buf = [0u8; size_of::<input_event>()];
-                file.read_exact(&mut event_buf).await?;
-                // I don't think this is that bad because an input_event is ultimately all ints, so there are no invalid
-                // bit patterns, and binrw would just be reading the exact same bytes in the exact same sequence.
-                let event = unsafe { &*(addr_of!(event_buf) as *const input_event) };
-                (event.type_, event.code, event.value)
+            let n = match file.read(&mut buf) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e).context("Failed reading inotify events"),
             };
-            // 2 = autorepeat, which we don't want to listen for
-            if type_ == ev_key && value < 2 {
-                let raw_code = code as u32;
-                sender.send((raw_code, value != 0)).await?;
+            if n == 0 {
+                break;
+            }
+            let mut offset = 0;
+            while offset + size_of::<inotify_event>() <= n {
+                // `buf` is a plain byte array with no alignment guarantee,
+                // so read via a pointer instead of casting to a
+                // `&inotify_event` (which would require alignment the
+                // buffer doesn't guarantee and be UB to dereference).
+                let ptr = buf[offset..].as_ptr() as *const inotify_event;
+                let event = unsafe { ptr.read_unaligned() };
+                let name_start = offset + size_of::<inotify_event>();
+                let name_len = event.len as usize;
+                let name_bytes = &buf[name_start..name_start + name_len];
+                let name_end = name_bytes.iter().position(|&b| b == 0).unwrap_or(name_len);
+                let name = String::from_utf8_lossy(&name_bytes[..name_end]);
+                let created = event.mask & ((libc::IN_CREATE | libc::IN_MOVED_TO) as u32) != 0;
+                let removed = event.mask & ((libc::IN_DELETE | libc::IN_MOVED_FROM) as u32) != 0;
+                if (created || removed) && name.ends_with("-event-kbd") {
+                    if let Some(dir) = watches.get(&event.wd) {
+                        events.push((PathBuf::from(format!("{dir}/{name}")), created));
+                    }
+                }
+                offset = name_start + name_len;
             }
         }
+        Ok(events)
     }
 
-    async fn listen_keys(mut self, receiver: Receiver<(u32, bool)>) -> Result<()> {
-        let mut conn = TcpStream::connect(format!("{}:{}", self.args.host, self.args.port))
-            .await
-            .context("Could not connect to LiveSplit server")?;
-        let mut paused = false;
-
-        let enabled_comparisons = Self::read_enabled_comparisons(self.args.settings.as_deref())?;
+    /// Bring `devices` back in sync with the hardware now that the inotify
+    /// fd is readable: add newly connected keyboards to the epoll set and
+    /// drop ones that disappeared.
+    fn reconcile_devices(
+        epfd: RawFd,
+        devices: &mut HashMap<RawFd, Device>,
+        inotify_file: &mut fs::File,
+        watches: &HashMap<i32, &'static str>,
+        verbose: u8,
+    ) -> Result<()> {
+        let mut pending: HashMap<PathBuf, bool> = Self::read_inotify_events(inotify_file, watches)?
+            .into_iter()
+            .collect();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        // Give the kernel a moment to finish settling the device node before
+        // acting, since CREATE is often followed by more events (e.g.
+        // permission/attribute changes) for the same path.
+        std::thread::sleep(INOTIFY_DEBOUNCE);
+        pending.extend(Self::read_inotify_events(inotify_file, watches)?);
 
-        let enabled_indices: Vec<usize> = enabled_comparisons
+        let already_open: HashMap<PathBuf, RawFd> = devices
             .iter()
-            .filter_map(|&name| {
-                    Self::COMPARISONS.iter().position(|&c| c == name)
-            })
+            .map(|(&fd, device)| (device.path.clone(), fd))
             .collect();
 
-        let last_comparison = Self::read_last_comparison(self.args.settings.as_deref())?
-            .unwrap_or_else(|| "Personal Best".to_string());
-
-        let mut comparison_index = enabled_comparisons
-            .iter()
-            .position(|&c| c == last_comparison)
-            .unwrap_or(0);
-        
-        let mut last_states: HashSet<(u32, bool)> = HashSet::new();
+        for (path, created) in pending {
+            if created {
+                if already_open.contains_key(&path) {
+                    continue;
+                }
+                match Self::open_device(epfd, path.clone()) {
+                    Ok(device) => {
+                        if verbose > 0 {
+                            println!("Keyboard connected: {:?}", path);
+                        }
+                        devices.insert(device.file.as_raw_fd(), device);
+                    }
+                    Err(e) => {
+                        if verbose > 0 {
+                            println!("Failed to open new keyboard {:?}: {:#}", path, e);
+                        }
+                    }
+                }
+            } else if let Some(&fd) = already_open.get(&path) {
+                if verbose > 0 {
+                    println!("Keyboard disconnected: {:?}", path);
+                }
+                epoll_del(epfd, fd)?;
+                devices.remove(&fd);
+            }
+        }
+        Ok(())
+    }
 
+    /// Drain every `input_event` currently available on `device`, feeding key
+    /// events straight into `key_state` and any resulting hotkey command to
+    /// `conn`. Returns `false` once the device is gone, whether because it
+    /// was unplugged cleanly (`read` returning EOF) or because the kernel
+    /// errored the fd outright (e.g. `ENODEV`, which is what a real evdev
+    /// char device actually returns on unplug). Either way this is a
+    /// per-device disconnect, not something that should tear down the whole
+    /// event loop.
+    fn drain_device(
+        device: &mut Device,
+        key_state: &mut KeyState,
+        conn: &mut ServerConnection,
+        paused: &mut bool,
+        comparison_index: &mut usize,
+        enabled_indices: &[usize],
+        verbose: u8,
+    ) -> bool {
+        let ev_key = EV["KEY"] as u16;
+        let ev_syn = EV["SYN"] as u16;
+        // Sized to hold several events per read, since a readable fd can
+        // carry more than one `input_event` at a time.
+        let mut buf = [0u8; size_of::<input_event>() * 32];
+        // Set on `SYN_DROPPED` and cleared at the matching `SYN_REPORT`.
+        // Per the evdev protocol, every event in between is part of the
+        // batch the kernel just told us was dropped, so it must be
+        // discarded rather than applied on top of (or racing) the fresh
+        // state `resync_keys` queries at the boundary.
+        let mut resyncing = false;
         loop {
-            let (code, is_pressed) = receiver.recv().await?;
-            if !last_states.insert((code, is_pressed)) {
-                continue; // duplicate, skip
-            }
-            // Remove the opposite state to keep the set small
-            last_states.remove(&(code, !is_pressed));
-            if self.args.verbose > 1 {
-                println!("Key {} = {}", code, is_pressed);
-            }
-            let active_hotkeys = self.key_state.handle_key(code, is_pressed);
-
-            for hotkey in active_hotkeys
-                .into_iter()
-                .filter_map(|(hotkey, is_active)| is_active.then_some(hotkey))
-            {
-                if self.args.verbose > 0 {
-                    println!("Sending hotkey {:?}", hotkey);
-                }
-                let command: &'static [u8] = match hotkey {
-                    Hotkey::SplitKey => b"startorsplit\r\n",
-                    Hotkey::ResetKey => b"reset\r\n",
-                    Hotkey::SkipKey => b"skipsplit\r\n",
-                    Hotkey::UndoKey => b"unsplit\r\n",
-                    Hotkey::PauseKey => {
-                        let command: &'static [u8] =
-                            if paused { b"resume\r\n" } else { b"pause\r\n" };
-                        paused = !paused;
-                        command
+            let n = match device.file.read(&mut buf) {
+                Ok(0) => return false,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(e) => {
+                    if verbose > 0 {
+                        println!("Failed reading {:?}, treating it as disconnected: {e}", device.path);
                     }
-                    Hotkey::SwitchComparisonNext => {
-                        comparison_index = (comparison_index + 1) % enabled_indices.len();
-                        Self::COMPARISON_COMMANDS[enabled_indices[comparison_index]]
+                    return false;
+                }
+            };
+            for (type_, code, value) in EventDecoder::new(&buf[..n]) {
+                if type_ == ev_syn && code == SYN_DROPPED {
+                    if verbose > 0 {
+                        println!(
+                            "Event buffer overflowed for {:?}, discarding until next SYN_REPORT",
+                            device.path
+                        );
                     }
-                    Hotkey::SwitchComparisonPrevious => {
-                        if comparison_index == 0 {
-                            comparison_index = enabled_indices.len() - 1;
-                        } else {
-                            comparison_index -= 1;
+                    resyncing = true;
+                } else if type_ == ev_syn && code == SYN_REPORT {
+                    if resyncing {
+                        resyncing = false;
+                        if verbose > 0 {
+                            println!("Resyncing key state for {:?}", device.path);
+                        }
+                        if let Err(e) = Self::resync_keys(
+                            device.file.as_raw_fd(),
+                            key_state,
+                            &mut device.pressed,
+                            conn,
+                            paused,
+                            comparison_index,
+                            enabled_indices,
+                            verbose,
+                        ) {
+                            if verbose > 0 {
+                                println!("Failed resyncing {:?}, treating it as disconnected: {e:#}", device.path);
+                            }
+                            return false;
                         }
-                        Self::COMPARISON_COMMANDS[enabled_indices[comparison_index]]
                     }
-                    _ => continue,
-                };
+                    // Otherwise just the end of an ordinary batch of
+                    // simultaneous events; nothing to do since each event
+                    // is already dispatched as it's decoded.
+                } else if resyncing {
+                    // Part of the batch the kernel just dropped; discard it
+                    // instead of dispatching on top of stale state.
+                } else if type_ == ev_key && value < 2 {
+                    // 2 = autorepeat, which we don't want to listen for
+                    let raw_code = code as u32;
+                    if value != 0 {
+                        device.pressed.insert(raw_code);
+                    } else {
+                        device.pressed.remove(&raw_code);
+                    }
+                    Self::dispatch_key(
+                        key_state,
+                        conn,
+                        raw_code,
+                        value != 0,
+                        paused,
+                        comparison_index,
+                        enabled_indices,
+                        verbose,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Re-query the device's real key state via `EVIOCGKEY` and dispatch
+    /// synthetic press/release events for every keycode where it disagrees
+    /// with `pressed`, so a dropped event batch can't leave a modifier stuck
+    /// "pressed" and corrupt combo detection.
+    fn resync_keys(
+        fd: RawFd,
+        key_state: &mut KeyState,
+        pressed: &mut HashSet<u32>,
+        conn: &mut ServerConnection,
+        paused: &mut bool,
+        comparison_index: &mut usize,
+        enabled_indices: &[usize],
+        verbose: u8,
+    ) -> Result<()> {
+        let bitmask = query_key_bitmask(fd)?;
+        let currently_pressed: HashSet<u32> = (0..=KEY_MAX as u32)
+            .filter(|&code| key_bit_set(&bitmask, code))
+            .collect();
+
+        for &code in pressed.difference(&currently_pressed).collect::<Vec<_>>() {
+            Self::dispatch_key(key_state, conn, code, false, paused, comparison_index, enabled_indices, verbose);
+        }
+        for &code in currently_pressed.difference(pressed).collect::<Vec<_>>() {
+            Self::dispatch_key(key_state, conn, code, true, paused, comparison_index, enabled_indices, verbose);
+        }
+        *pressed = currently_pressed;
+        Ok(())
+    }
+
+    fn dispatch_key(
+        key_state: &mut KeyState,
+        conn: &mut ServerConnection,
+        code: u32,
+        is_pressed: bool,
+        paused: &mut bool,
+        comparison_index: &mut usize,
+        enabled_indices: &[usize],
+        verbose: u8,
+    ) {
+        if verbose > 1 {
+            println!("Key {} = {}", code, is_pressed);
+        }
+        let active_hotkeys = key_state.handle_key(code, is_pressed);
 
-                conn.write_all(command).await?;
+        for hotkey in active_hotkeys
+            .into_iter()
+            .filter_map(|(hotkey, is_active)| is_active.then_some(hotkey))
+        {
+            if verbose > 0 {
+                println!("Sending hotkey {:?}", hotkey);
             }
+            let command: &'static [u8] = match hotkey {
+                Hotkey::SplitKey => b"startorsplit\r\n",
+                Hotkey::ResetKey => b"reset\r\n",
+                Hotkey::SkipKey => b"skipsplit\r\n",
+                Hotkey::UndoKey => b"unsplit\r\n",
+                Hotkey::PauseKey => {
+                    let command: &'static [u8] = if *paused { b"resume\r\n" } else { b"pause\r\n" };
+                    *paused = !*paused;
+                    command
+                }
+                Hotkey::SwitchComparisonNext => {
+                    *comparison_index = (*comparison_index + 1) % enabled_indices.len();
+                    Self::COMPARISON_COMMANDS[enabled_indices[*comparison_index]]
+                }
+                Hotkey::SwitchComparisonPrevious => {
+                    if *comparison_index == 0 {
+                        *comparison_index = enabled_indices.len() - 1;
+                    } else {
+                        *comparison_index -= 1;
+                    }
+                    Self::COMPARISON_COMMANDS[enabled_indices[*comparison_index]]
+                }
+                _ => continue,
+            };
+
+            conn.send(command);
         }
     }
 
-    pub async fn listen(self) -> Result<()> {
+    pub fn listen(mut self) -> Result<()> {
+        let epfd = unsafe { libc::epoll_create1(0) };
+        if epfd < 0 {
+            return Err(io::Error::last_os_error()).context("epoll_create1 failed");
+        }
+
+        let initial_conn = TcpStream::connect(format!("{}:{}", self.args.host, self.args.port))
+            .context("Could not connect to LiveSplit server")?;
+        let mut conn = ServerConnection::new(
+            self.args.host.clone(),
+            self.args.port,
+            self.args.verbose,
+            self.args.drop_commands_on_disconnect,
+            epfd,
+            initial_conn,
+        );
+
+        let enabled_comparisons = Self::read_enabled_comparisons(self.args.settings.as_deref())?;
+        let enabled_indices: Vec<usize> = enabled_comparisons
+            .iter()
+            .filter_map(|&name| Self::COMPARISONS.iter().position(|&c| c == name))
+            .collect();
+        let last_comparison = Self::read_last_comparison(self.args.settings.as_deref())?
+            .unwrap_or_else(|| "Personal Best".to_string());
+        let mut comparison_index = enabled_comparisons
+            .iter()
+            .position(|&c| c == last_comparison)
+            .unwrap_or(0);
+        let mut paused = false;
+
         // find keyboards
-        let devices = if !self.args.devices.is_empty() {
+        let explicit_devices = !self.args.devices.is_empty();
+        let device_paths: Vec<PathBuf> = if explicit_devices {
             self.args.devices.iter().map(PathBuf::from).collect()
         } else {
-            let mut devices = Vec::new();
-            let mut entries = read_dir("/dev/input/by-path/").await?;
-            while let Some(entry) = entries.next().await {
+            let mut paths = Vec::new();
+            for entry in fs::read_dir("/dev/input/by-path/")? {
                 let path = entry?.path();
                 if path
                     .file_name()
                     .map_or(false, |n| n.to_string_lossy().ends_with("-event-kbd"))
                 {
-                    devices.push(path);
+                    paths.push(path);
                 }
             }
-            devices
+            paths
         };
 
-        if devices.is_empty() {
-            return Err(anyhow!("No keyboard devices found"));
+        if self.args.verbose > 0 {
+            println!("Keyboards: {:?}", device_paths);
         }
 
-        if self.args.verbose > 0 {
-            println!("Keyboards: {:?}", devices);
+        let mut devices: HashMap<RawFd, Device> = HashMap::new();
+        for path in device_paths {
+            let device = Self::open_device(epfd, path)?;
+            devices.insert(device.file.as_raw_fd(), device);
+        }
+
+        // Devices pinned on the command line are taken as-is; only the
+        // auto-discovered set is kept in sync with hardware being plugged in
+        // or removed.
+        let mut inotify = if explicit_devices {
+            None
+        } else {
+            let (file, watches) = Self::init_inotify()?;
+            epoll_add(epfd, file.as_raw_fd(), libc::EPOLLIN as u32)?;
+            Some((file, watches))
+        };
+        let inotify_fd = inotify.as_ref().map(|(file, _)| file.as_raw_fd());
+
+        let mut epoll_events: [libc::epoll_event; MAX_EPOLL_EVENTS] = unsafe { std::mem::zeroed() };
+        loop {
+            let n = unsafe {
+                libc::epoll_wait(
+                    epfd,
+                    epoll_events.as_mut_ptr(),
+                    epoll_events.len() as i32,
+                    -1,
+                )
+            };
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err).context("epoll_wait failed");
+            }
+
+            for epoll_event in &epoll_events[..n as usize] {
+                let fd = unsafe { addr_of!(epoll_event.u64).read_unaligned() } as RawFd;
+
+                if Some(fd) == inotify_fd {
+                    let (inotify_file, watches) = inotify.as_mut().unwrap();
+                    if let Err(e) =
+                        Self::reconcile_devices(epfd, &mut devices, inotify_file, watches, self.args.verbose)
+                    {
+                        if self.args.verbose > 0 {
+                            println!("Failed reconciling hot-plugged keyboards: {e:#}");
+                        }
+                    }
+                    continue;
+                }
+
+                if Some(fd) == conn.connecting_fd() {
+                    conn.poll_connect();
+                    continue;
+                }
+
+                let Some(device) = devices.get_mut(&fd) else {
+                    continue;
+                };
+                let still_connected = Self::drain_device(
+                    device,
+                    &mut self.key_state,
+                    &mut conn,
+                    &mut paused,
+                    &mut comparison_index,
+                    &enabled_indices,
+                    self.args.verbose,
+                );
+                if !still_connected {
+                    if self.args.verbose > 0 {
+                        println!("Keyboard disconnected: {:?}", device.path);
+                    }
+                    if let Err(e) = epoll_del(epfd, fd) {
+                        if self.args.verbose > 0 {
+                            println!("Failed unregistering disconnected keyboard fd: {e:#}");
+                        }
+                    }
+                    devices.remove(&fd);
+                }
+            }
         }
-        let (sender, receiver) = unbounded();
-        let mut tasks: Vec<_> = devices
-            .into_iter()
-            .map(|d| task::spawn(Self::listen_keyboard(sender.clone(), d)))
-            .collect();
-        tasks.push(task::spawn(self.listen_keys(receiver)));
-        future::try_join_all(tasks).await.map(|_| ())
     }
 }
 
-#[async_std::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     let listener = HotkeyListener::new(Args::parse())?;
-    listener.listen().await
+    listener.listen()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build the bytes of one `inotify_event`, padding `name` with the NUL
+    /// terminator and zero-padding the kernel always includes.
+    fn encode_inotify_event(wd: i32, mask: u32, name: &str) -> Vec<u8> {
+        let mut name_bytes = name.as_bytes().to_vec();
+        name_bytes.push(0);
+        while name_bytes.len() % 4 != 0 {
+            name_bytes.push(0);
+        }
+        let event = inotify_event {
+            wd,
+            mask,
+            cookie: 0,
+            len: name_bytes.len() as u32,
+        };
+        let mut buf = unsafe {
+            std::slice::from_raw_parts(&event as *const _ as *const u8, size_of::<inotify_event>())
+        }
+        .to_vec();
+        buf.extend_from_slice(&name_bytes);
+        buf
+    }
+
+    fn pipe_files() -> (fs::File, fs::File) {
+        let mut fds = [0i32; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        unsafe { (fs::File::from_raw_fd(fds[0]), fs::File::from_raw_fd(fds[1])) }
+    }
+
+    #[test]
+    fn read_inotify_events_filters_created_and_removed_kbd_nodes() {
+        let (mut read_end, mut write_end) = pipe_files();
+        set_nonblocking(read_end.as_raw_fd()).unwrap();
+        let mut watches = HashMap::new();
+        watches.insert(1, "/dev/input/by-path");
+
+        let mut buf = Vec::new();
+        buf.extend(encode_inotify_event(1, libc::IN_CREATE, "usb-kbd-event-kbd"));
+        buf.extend(encode_inotify_event(1, libc::IN_DELETE, "usb-kbd-event-kbd"));
+        buf.extend(encode_inotify_event(1, libc::IN_CREATE, "usb-mouse-event-mouse"));
+        buf.extend(encode_inotify_event(99, libc::IN_CREATE, "other-event-kbd"));
+        write_end.write_all(&buf).unwrap();
+
+        let events = HotkeyListener::read_inotify_events(&mut read_end, &watches).unwrap();
+        assert_eq!(
+            events,
+            vec![
+                (PathBuf::from("/dev/input/by-path/usb-kbd-event-kbd"), true),
+                (PathBuf::from("/dev/input/by-path/usb-kbd-event-kbd"), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_inotify_events_returns_empty_when_nothing_pending() {
+        let (mut read_end, _write_end) = pipe_files();
+        set_nonblocking(read_end.as_raw_fd()).unwrap();
+        let events = HotkeyListener::read_inotify_events(&mut read_end, &HashMap::new()).unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn read_inotify_events_ignores_a_truncated_trailing_header() {
+        let (mut read_end, mut write_end) = pipe_files();
+        set_nonblocking(read_end.as_raw_fd()).unwrap();
+        let mut watches = HashMap::new();
+        watches.insert(1, "/dev/input/by-path");
+
+        let mut buf = encode_inotify_event(1, libc::IN_CREATE, "usb-kbd-event-kbd");
+        buf.truncate(size_of::<inotify_event>() - 2);
+        write_end.write_all(&buf).unwrap();
+
+        let events = HotkeyListener::read_inotify_events(&mut read_end, &watches).unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn epoll_add_then_del_stops_reporting_the_fd() {
+        let (read_end, write_end) = pipe_files();
+        set_nonblocking(read_end.as_raw_fd()).unwrap();
+
+        let epfd = unsafe { libc::epoll_create1(0) };
+        assert!(epfd >= 0);
+        epoll_add(epfd, read_end.as_raw_fd(), libc::EPOLLIN as u32).unwrap();
+
+        (&write_end).write_all(b"x").unwrap();
+        let mut events: [libc::epoll_event; 1] = unsafe { std::mem::zeroed() };
+        assert_eq!(unsafe { libc::epoll_wait(epfd, events.as_mut_ptr(), 1, 0) }, 1);
+
+        epoll_del(epfd, read_end.as_raw_fd()).unwrap();
+        (&write_end).write_all(b"y").unwrap();
+        assert_eq!(unsafe { libc::epoll_wait(epfd, events.as_mut_ptr(), 1, 0) }, 0);
+
+        unsafe { libc::close(epfd) };
+    }
+
+    fn test_connection(drop_on_disconnect: bool) -> ServerConnection {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stream = TcpStream::connect(addr).unwrap();
+        let epfd = unsafe { libc::epoll_create1(0) };
+        assert!(epfd >= 0);
+        ServerConnection::new("127.0.0.1".to_string(), addr.port(), 0, drop_on_disconnect, epfd, stream)
+    }
+
+    #[test]
+    fn schedule_retry_doubles_backoff_up_to_the_cap() {
+        let mut conn = test_connection(false);
+        assert_eq!(conn.backoff, INITIAL_RECONNECT_BACKOFF);
+        conn.schedule_retry();
+        assert_eq!(conn.backoff, INITIAL_RECONNECT_BACKOFF * 2);
+        conn.schedule_retry();
+        assert_eq!(conn.backoff, INITIAL_RECONNECT_BACKOFF * 4);
+        for _ in 0..10 {
+            conn.schedule_retry();
+        }
+        assert_eq!(conn.backoff, MAX_RECONNECT_BACKOFF);
+    }
+
+    #[test]
+    fn write_while_disconnected_buffers_commands_by_default() {
+        let mut conn = test_connection(false);
+        conn.state = ConnState::Disconnected;
+        conn.write(b"split\r\n");
+        assert_eq!(conn.buffered, b"split\r\n".to_vec());
+    }
+
+    #[test]
+    fn write_while_disconnected_drops_commands_when_configured_to() {
+        let mut conn = test_connection(true);
+        conn.state = ConnState::Disconnected;
+        conn.write(b"split\r\n");
+        assert!(conn.buffered.is_empty());
+    }
+
+    #[test]
+    fn connecting_fd_reflects_state() {
+        let mut conn = test_connection(false);
+        assert_eq!(conn.connecting_fd(), None);
+        conn.state = ConnState::Connecting(42);
+        assert_eq!(conn.connecting_fd(), Some(42));
+    }
+
+    fn encode_input_event(type_: u16, code: u16, value: i32) -> Vec<u8> {
+        let event = input_event {
+            time: libc::timeval { tv_sec: 0, tv_usec: 0 },
+            type_,
+            code,
+            value,
+        };
+        unsafe { std::slice::from_raw_parts(&event as *const _ as *const u8, size_of::<input_event>()) }.to_vec()
+    }
+
+    #[test]
+    fn event_decoder_yields_every_event_in_a_batch() {
+        let mut buf = Vec::new();
+        buf.extend(encode_input_event(1, 30, 1));
+        buf.extend(encode_input_event(1, 30, 0));
+        buf.extend(encode_input_event(0, 0, 0));
+
+        let events: Vec<_> = EventDecoder::new(&buf).collect();
+        assert_eq!(events, vec![(1, 30, 1), (1, 30, 0), (0, 0, 0)]);
+    }
+
+    #[test]
+    fn event_decoder_stops_before_a_truncated_trailing_event() {
+        let mut buf = encode_input_event(1, 30, 1);
+        buf.extend(encode_input_event(1, 31, 1));
+        buf.truncate(buf.len() - 2);
+
+        let events: Vec<_> = EventDecoder::new(&buf).collect();
+        assert_eq!(events, vec![(1, 30, 1)]);
+    }
+
+    #[test]
+    fn key_bit_set_reads_known_bit_patterns() {
+        let mut bitmask = [0u8; KEY_BITMASK_LEN];
+        bitmask[0] = 0b0000_0010; // bit 1
+        bitmask[1] = 0b0000_0001; // bit 8
+        assert!(key_bit_set(&bitmask, 1));
+        assert!(key_bit_set(&bitmask, 8));
+        assert!(!key_bit_set(&bitmask, 0));
+        assert!(!key_bit_set(&bitmask, 9));
+    }
+
+    #[test]
+    fn ioc_matches_the_kernel_ioc_macro() {
+        // EVIOCGKEY(len) per linux/input.h, written out as a literal rather
+        // than computed with `ioc`'s own shift/mask arithmetic so a
+        // transcription error there doesn't go unnoticed: dir 2 in bits
+        // 30-31, size 96 (0x60) in bits 16-29, type 'E' (0x45) in bits 8-15,
+        // nr 0x18 in bits 0-7.
+        let request = ioc(2 /* _IOC_READ */, b'E', 0x18, KEY_BITMASK_LEN);
+        let expected: u64 = 0x8060_4518;
+        assert_eq!(request as u64, expected);
+    }
 }